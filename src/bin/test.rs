@@ -22,7 +22,7 @@ fn main() {
     let mut stream = TcpStream::connect("192.168.1.20:6566").expect("Failed to connect");
     stream.set_nodelay(true);
 
-    init(&mut stream);
+    init(&mut stream).expect("Failed to initialize connection");
 
     let devices = request_device_list(&mut stream).unwrap();
 
@@ -64,5 +64,5 @@ fn main() {
     };
 
     println!("Closing device {}", &device.name);
-    close_device(handle.unwrap(), &mut stream);
+    close_device(handle.unwrap(), &mut stream).ok();
 }
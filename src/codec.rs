@@ -0,0 +1,631 @@
+use std::io::{Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::control::{OptionAction, OptionInfo, OptionValue};
+use crate::error::Error;
+use crate::scan::SaneParameters;
+use crate::{Result, TryFromStream};
+
+/// Which RPC a `Reply` belongs to.
+///
+/// Unlike a request, a reply carries no opcode of its own on the wire, so
+/// `decode` needs to be told what it's looking for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Init,
+    Open,
+    Close,
+    GetParameters,
+    StartScan,
+    Cancel,
+    ControlOption,
+    Authorize,
+}
+
+/// A single SANE RPC request or reply, decoded independent of the
+/// transport it arrived on.
+///
+/// Reply variants carry their status as a raw `i32`; pass it through
+/// `status::Status::from` to interpret it.
+///
+/// `GetDeviceListRequest` and `GetOptionDescriptorsRequest` have no reply
+/// variant here: their replies carry a `Vec<Option<Device>>`/
+/// `Vec<Option<OptionDescriptor>>`, which only exist as `TryFromStream`
+/// impls with no corresponding write side, so those lists keep being read
+/// directly off the stream after the request goes through this codec.
+#[derive(Debug, Clone)]
+pub enum Message {
+    InitRequest {
+        version: u32,
+        username: String,
+    },
+    InitReply {
+        status: i32,
+        version: u32,
+    },
+
+    GetDeviceListRequest,
+
+    OpenRequest {
+        name: String,
+    },
+    OpenReply {
+        status: i32,
+        handle: i32,
+        resource: Option<String>,
+    },
+
+    GetOptionDescriptorsRequest {
+        handle: i32,
+    },
+
+    CloseRequest {
+        handle: i32,
+    },
+    CloseReply {
+        dummy: i32,
+    },
+
+    GetParametersRequest {
+        handle: i32,
+    },
+    GetParametersReply {
+        status: i32,
+        parameters: SaneParameters,
+    },
+
+    StartScanRequest {
+        handle: i32,
+    },
+    StartScanReply {
+        status: i32,
+        port: i32,
+        byte_order: i32,
+        resource: Option<String>,
+    },
+
+    CancelRequest {
+        handle: i32,
+    },
+    CancelReply {
+        status: i32,
+    },
+
+    ControlOptionRequest {
+        handle: i32,
+        index: i32,
+        action: OptionAction,
+        value: OptionValue,
+    },
+    ControlOptionReply {
+        status: i32,
+        info: OptionInfo,
+        value: OptionValue,
+        resource: Option<String>,
+    },
+
+    AuthorizeRequest {
+        resource: String,
+        username: String,
+        password: String,
+    },
+    AuthorizeReply {
+        status: i32,
+    },
+}
+
+/// Outcome of a failed decode attempt: either the buffer is simply
+/// incomplete and decoding should be retried once more bytes arrive, or
+/// the bytes are malformed.
+enum DecodeOutcome {
+    NeedMoreData,
+    Error(Error),
+}
+
+type DecodeResult<T> = std::result::Result<T, DecodeOutcome>;
+
+impl From<std::io::Error> for DecodeOutcome {
+    fn from(err: std::io::Error) -> DecodeOutcome {
+        DecodeOutcome::from(Error::from(err))
+    }
+}
+
+impl From<Error> for DecodeOutcome {
+    fn from(err: Error) -> DecodeOutcome {
+        match err {
+            Error::IOError(ref io_err) if io_err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                DecodeOutcome::NeedMoreData
+            }
+            _ => DecodeOutcome::Error(err),
+        }
+    }
+}
+
+fn encode_string<W: Write>(string: &str, out: &mut W) -> Result<()> {
+    out.write_i32::<BigEndian>(string.len() as i32 + 1)?;
+    out.write_all(string.as_bytes())?;
+    out.write_all(&[0u8])?;
+
+    Ok(())
+}
+
+fn encode_optional_string<W: Write>(value: &Option<String>, out: &mut W) -> Result<()> {
+    match value {
+        Some(value) => encode_string(value, out),
+        None => {
+            out.write_i32::<BigEndian>(0)?;
+            Ok(())
+        }
+    }
+}
+
+/// Append the wire encoding of `message` to `buf`.
+pub fn encode(message: &Message, buf: &mut Vec<u8>) -> Result<()> {
+    match message {
+        Message::InitRequest { version, username } => {
+            buf.write_i32::<BigEndian>(0)?;
+            buf.write_u32::<BigEndian>(*version)?;
+            encode_string(username, buf)?;
+        }
+        Message::InitReply { status, version } => {
+            buf.write_i32::<BigEndian>(*status)?;
+            buf.write_u32::<BigEndian>(*version)?;
+        }
+
+        Message::GetDeviceListRequest => {
+            buf.write_i32::<BigEndian>(1)?;
+        }
+
+        Message::OpenRequest { name } => {
+            buf.write_i32::<BigEndian>(2)?;
+            encode_string(name, buf)?;
+        }
+        Message::OpenReply {
+            status,
+            handle,
+            resource,
+        } => {
+            buf.write_i32::<BigEndian>(*status)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+            encode_optional_string(resource, buf)?;
+        }
+
+        Message::GetOptionDescriptorsRequest { handle } => {
+            buf.write_i32::<BigEndian>(4)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+        }
+
+        Message::CloseRequest { handle } => {
+            buf.write_i32::<BigEndian>(3)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+        }
+        Message::CloseReply { dummy } => {
+            buf.write_i32::<BigEndian>(*dummy)?;
+        }
+
+        Message::GetParametersRequest { handle } => {
+            buf.write_i32::<BigEndian>(6)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+        }
+        Message::GetParametersReply { status, parameters } => {
+            buf.write_i32::<BigEndian>(*status)?;
+            buf.write_i32::<BigEndian>(parameters.format as i32)?;
+            buf.write_i32::<BigEndian>(parameters.last_frame as i32)?;
+            buf.write_i32::<BigEndian>(parameters.bytes_per_line)?;
+            buf.write_i32::<BigEndian>(parameters.pixels_per_line)?;
+            buf.write_i32::<BigEndian>(parameters.lines)?;
+            buf.write_i32::<BigEndian>(parameters.depth)?;
+        }
+
+        Message::StartScanRequest { handle } => {
+            buf.write_i32::<BigEndian>(7)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+        }
+        Message::StartScanReply {
+            status,
+            port,
+            byte_order,
+            resource,
+        } => {
+            buf.write_i32::<BigEndian>(*status)?;
+            buf.write_i32::<BigEndian>(*port)?;
+            buf.write_i32::<BigEndian>(*byte_order)?;
+            encode_optional_string(resource, buf)?;
+        }
+
+        Message::CancelRequest { handle } => {
+            buf.write_i32::<BigEndian>(8)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+        }
+        Message::CancelReply { status } => {
+            buf.write_i32::<BigEndian>(*status)?;
+        }
+
+        Message::ControlOptionRequest {
+            handle,
+            index,
+            action,
+            value,
+        } => {
+            buf.write_i32::<BigEndian>(5)?;
+            buf.write_i32::<BigEndian>(*handle)?;
+            buf.write_i32::<BigEndian>(*index)?;
+            buf.write_i32::<BigEndian>(action.as_i32())?;
+            buf.write_i32::<BigEndian>(value.type_tag())?;
+            buf.write_i32::<BigEndian>(value.size())?;
+            value.write_payload(buf)?;
+        }
+        Message::ControlOptionReply {
+            status,
+            info,
+            value,
+            resource,
+        } => {
+            buf.write_i32::<BigEndian>(*status)?;
+            buf.write_i32::<BigEndian>(info.bits())?;
+            buf.write_i32::<BigEndian>(value.type_tag())?;
+            buf.write_i32::<BigEndian>(value.size())?;
+            value.write_payload(buf)?;
+            encode_optional_string(resource, buf)?;
+        }
+
+        Message::AuthorizeRequest {
+            resource,
+            username,
+            password,
+        } => {
+            buf.write_i32::<BigEndian>(9)?;
+            encode_string(resource, buf)?;
+            encode_string(username, buf)?;
+            encode_string(password, buf)?;
+        }
+        Message::AuthorizeReply { status } => {
+            buf.write_i32::<BigEndian>(*status)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_request(cursor: &mut Cursor<&[u8]>) -> DecodeResult<Message> {
+    let opcode = cursor.read_i32::<BigEndian>()?;
+
+    Ok(match opcode {
+        0 => {
+            let version = cursor.read_u32::<BigEndian>()?;
+            let username = String::try_from_stream(cursor)?;
+            Message::InitRequest { version, username }
+        }
+        1 => Message::GetDeviceListRequest,
+        2 => Message::OpenRequest {
+            name: String::try_from_stream(cursor)?,
+        },
+        3 => Message::CloseRequest {
+            handle: cursor.read_i32::<BigEndian>()?,
+        },
+        4 => Message::GetOptionDescriptorsRequest {
+            handle: cursor.read_i32::<BigEndian>()?,
+        },
+        5 => {
+            let handle = cursor.read_i32::<BigEndian>()?;
+            let index = cursor.read_i32::<BigEndian>()?;
+            let action = OptionAction::from_i32(cursor.read_i32::<BigEndian>()?)?;
+            let type_tag = cursor.read_i32::<BigEndian>()?;
+            let size = cursor.read_i32::<BigEndian>()?;
+            let value = OptionValue::read(type_tag, size, cursor)?;
+            Message::ControlOptionRequest {
+                handle,
+                index,
+                action,
+                value,
+            }
+        }
+        6 => Message::GetParametersRequest {
+            handle: cursor.read_i32::<BigEndian>()?,
+        },
+        7 => Message::StartScanRequest {
+            handle: cursor.read_i32::<BigEndian>()?,
+        },
+        8 => Message::CancelRequest {
+            handle: cursor.read_i32::<BigEndian>()?,
+        },
+        9 => {
+            let resource = String::try_from_stream(cursor)?;
+            let username = String::try_from_stream(cursor)?;
+            let password = String::try_from_stream(cursor)?;
+            Message::AuthorizeRequest {
+                resource,
+                username,
+                password,
+            }
+        }
+        _ => {
+            return Err(DecodeOutcome::Error(Error::BadNetworkDataError(format!(
+                "Unknown SANE opcode {}",
+                opcode
+            ))))
+        }
+    })
+}
+
+fn parse_reply(kind: MessageKind, cursor: &mut Cursor<&[u8]>) -> DecodeResult<Message> {
+    Ok(match kind {
+        MessageKind::Init => {
+            let status = cursor.read_i32::<BigEndian>()?;
+            let version = cursor.read_u32::<BigEndian>()?;
+            Message::InitReply { status, version }
+        }
+        MessageKind::Open => {
+            let status = cursor.read_i32::<BigEndian>()?;
+            let handle = cursor.read_i32::<BigEndian>()?;
+            let resource = <Option<String>>::try_from_stream(cursor)?;
+            Message::OpenReply {
+                status,
+                handle,
+                resource,
+            }
+        }
+        MessageKind::Close => Message::CloseReply {
+            dummy: cursor.read_i32::<BigEndian>()?,
+        },
+        MessageKind::GetParameters => {
+            let status = cursor.read_i32::<BigEndian>()?;
+            let parameters = SaneParameters::try_from_stream(cursor)?;
+            Message::GetParametersReply { status, parameters }
+        }
+        MessageKind::StartScan => {
+            let status = cursor.read_i32::<BigEndian>()?;
+            let port = cursor.read_i32::<BigEndian>()?;
+            let byte_order = cursor.read_i32::<BigEndian>()?;
+            let resource = <Option<String>>::try_from_stream(cursor)?;
+            Message::StartScanReply {
+                status,
+                port,
+                byte_order,
+                resource,
+            }
+        }
+        MessageKind::Cancel => Message::CancelReply {
+            status: cursor.read_i32::<BigEndian>()?,
+        },
+        MessageKind::ControlOption => {
+            let status = cursor.read_i32::<BigEndian>()?;
+            let info = OptionInfo::from_bits_truncate(cursor.read_i32::<BigEndian>()?);
+            let type_tag = cursor.read_i32::<BigEndian>()?;
+            let size = cursor.read_i32::<BigEndian>()?;
+            let value = OptionValue::read(type_tag, size, cursor)?;
+            let resource = <Option<String>>::try_from_stream(cursor)?;
+            Message::ControlOptionReply {
+                status,
+                info,
+                value,
+                resource,
+            }
+        }
+        MessageKind::Authorize => Message::AuthorizeReply {
+            status: cursor.read_i32::<BigEndian>()?,
+        },
+    })
+}
+
+/// Try to decode one message from the front of `buf`.
+///
+/// Pass `expected_reply: None` to decode a self-framed request (it starts
+/// with its own opcode); pass `Some(kind)` to decode the reply to a
+/// request of that kind, since replies carry no opcode of their own.
+///
+/// Returns `Ok(None)` when `buf` doesn't yet hold a complete message,
+/// without consuming anything, so callers can retry once more bytes have
+/// arrived.
+pub fn decode(expected_reply: Option<MessageKind>, buf: &mut Vec<u8>) -> Result<Option<Message>> {
+    let mut cursor = Cursor::new(buf.as_slice());
+
+    let result = match expected_reply {
+        None => parse_request(&mut cursor),
+        Some(kind) => parse_reply(kind, &mut cursor),
+    };
+
+    match result {
+        Ok(message) => {
+            let consumed = cursor.position() as usize;
+            buf.drain(..consumed);
+            Ok(Some(message))
+        }
+        Err(DecodeOutcome::NeedMoreData) => Ok(None),
+        Err(DecodeOutcome::Error(err)) => Err(err),
+    }
+}
+
+/// Encode `message` and write it to `stream` in one shot.
+pub(crate) fn write_message<S: Write>(message: &Message, stream: &mut S) -> Result<()> {
+    let mut buf = Vec::new();
+    encode(message, &mut buf)?;
+    stream.write_all(&buf)?;
+
+    Ok(())
+}
+
+/// Read from `stream` until a full message decodes, per `decode`.
+///
+/// `stream` need not deliver the whole message in one read: bytes
+/// accumulate into a local buffer across as many reads as it takes.
+pub(crate) fn read_message<S: Read>(
+    expected_reply: Option<MessageKind>,
+    stream: &mut S,
+) -> Result<Message> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        if let Some(message) = decode(expected_reply, &mut buf)? {
+            return Ok(message);
+        }
+
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        buf.extend_from_slice(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::FrameFormat;
+
+    #[test]
+    fn decode_returns_none_on_incomplete_buffer() {
+        let mut buf = vec![0u8, 0, 0]; // Short by one byte for an i32 opcode.
+        assert!(decode(None, &mut buf).unwrap().is_none());
+        // Nothing should have been consumed.
+        assert_eq!(buf.len(), 3);
+    }
+
+    #[test]
+    fn round_trips_cancel_request() {
+        let message = Message::CancelRequest { handle: 42 };
+
+        let mut buf = Vec::new();
+        encode(&message, &mut buf).unwrap();
+
+        let decoded = decode(None, &mut buf).unwrap().unwrap();
+
+        match decoded {
+            Message::CancelRequest { handle } => assert_eq!(handle, 42),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn round_trips_control_option_request_with_string_value() {
+        let message = Message::ControlOptionRequest {
+            handle: 1,
+            index: 2,
+            action: OptionAction::SetValue,
+            value: OptionValue::String("letter".to_owned()),
+        };
+
+        let mut buf = Vec::new();
+        encode(&message, &mut buf).unwrap();
+
+        match decode(None, &mut buf).unwrap().unwrap() {
+            Message::ControlOptionRequest {
+                handle,
+                index,
+                action,
+                value: OptionValue::String(value),
+            } => {
+                assert_eq!(handle, 1);
+                assert_eq!(index, 2);
+                assert_eq!(action, OptionAction::SetValue);
+                assert_eq!(value, "letter");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_get_parameters_reply() {
+        let parameters = SaneParameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 850,
+            pixels_per_line: 850,
+            lines: 1100,
+            depth: 8,
+        };
+
+        let message = Message::GetParametersReply {
+            status: 0,
+            parameters,
+        };
+
+        let mut buf = Vec::new();
+        encode(&message, &mut buf).unwrap();
+
+        match decode(Some(MessageKind::GetParameters), &mut buf)
+            .unwrap()
+            .unwrap()
+        {
+            Message::GetParametersReply { status, parameters } => {
+                assert_eq!(status, 0);
+                assert_eq!(parameters.format, FrameFormat::Gray);
+                assert!(parameters.last_frame);
+                assert_eq!(parameters.lines, 1100);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decoding_an_unknown_opcode_is_an_error() {
+        let mut buf = Vec::new();
+        buf.write_i32::<BigEndian>(999).unwrap();
+
+        assert!(decode(None, &mut buf).is_err());
+    }
+
+    #[test]
+    fn round_trips_open_reply_with_auth_resource() {
+        let message = Message::OpenReply {
+            status: 0,
+            handle: 7,
+            resource: Some("backend".to_owned()),
+        };
+
+        let mut buf = Vec::new();
+        encode(&message, &mut buf).unwrap();
+
+        match decode(Some(MessageKind::Open), &mut buf).unwrap().unwrap() {
+            Message::OpenReply {
+                status,
+                handle,
+                resource,
+            } => {
+                assert_eq!(status, 0);
+                assert_eq!(handle, 7);
+                assert_eq!(resource.as_deref(), Some("backend"));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    /// A `Read` that only ever hands back a single byte per call, so
+    /// `read_message` must accumulate across many reads to assemble one
+    /// message.
+    struct OneByteAtATime<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByteAtATime<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn read_message_assembles_a_reply_delivered_one_byte_at_a_time() {
+        let message = Message::CancelReply { status: 0 };
+
+        let mut buf = Vec::new();
+        encode(&message, &mut buf).unwrap();
+
+        let mut stream = OneByteAtATime(&buf);
+        let decoded = read_message(Some(MessageKind::Cancel), &mut stream).unwrap();
+
+        match decoded {
+            Message::CancelReply { status } => assert_eq!(status, 0),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::codec::{read_message, write_message, Message, MessageKind};
+use crate::error::Error;
+use crate::{check_status, init, open_device, open_device_authenticated, Device, OpenResult, Result};
+
+/// How long to wait between polls of a non-blocking socket that has
+/// nothing to read yet.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A buffered, non-blocking connection to `saned`.
+///
+/// Unlike the free RPC functions, which assume a blocking stream,
+/// `SaneConnection` tolerates short reads,
+/// partial writes, and `WouldBlock`: outbound bytes are queued and
+/// flushed as the socket allows, and inbound bytes accumulate into an
+/// internal buffer until a caller actually needs them. If the link drops,
+/// the connection reconnects and re-runs `init` (and, if a device was
+/// opened through `SaneConnection::open_device`/`open_device_authenticated`,
+/// re-opens it) before reporting the drop to the caller as an
+/// `io::ErrorKind::ConnectionReset` error: whatever request was in
+/// flight when the link dropped is gone, so the caller must re-issue it,
+/// not assume it went through.
+///
+/// The free `open_device`/`open_device_authenticated` functions have no
+/// way of knowing they were handed a `SaneConnection` rather than a
+/// plain stream, so calling them directly on one leaves nothing
+/// remembered and a resync will only redo `init`. Go through
+/// `SaneConnection::open_device`/`open_device_authenticated` (or call
+/// `remember_device` yourself) if you need a dropped link to come back
+/// with the device still open.
+pub struct SaneConnection {
+    stream: TcpStream,
+    host: String,
+    port: u16,
+    device_name: Option<String>,
+    recv_buffer: Vec<u8>,
+    send_queue: VecDeque<Cursor<Vec<u8>>>,
+}
+
+impl SaneConnection {
+    /// Connect to `saned` at `host:port` and perform the initial
+    /// `SANE_NET_INIT` handshake.
+    pub fn connect(host: &str, port: u16) -> Result<SaneConnection> {
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_nonblocking(true)?;
+
+        let mut connection = SaneConnection {
+            stream,
+            host: host.to_owned(),
+            port,
+            device_name: None,
+            recv_buffer: Vec::new(),
+            send_queue: VecDeque::new(),
+        };
+
+        init(&mut connection)?;
+
+        Ok(connection)
+    }
+
+    /// Open `device` over this connection, remembering its name so a
+    /// resync after a dropped link can transparently reopen it.
+    pub fn open_device(&mut self, device: &Device) -> Result<OpenResult> {
+        let result = open_device(device, self)?;
+
+        if let OpenResult::Handle(_) = result {
+            self.remember_device(device);
+        }
+
+        Ok(result)
+    }
+
+    /// As `open_device`, but transparently authorizes with
+    /// `username`/`password` the way the free `open_device_authenticated`
+    /// does.
+    pub fn open_device_authenticated(
+        &mut self,
+        device: &Device,
+        username: &str,
+        password: &str,
+    ) -> Result<i32> {
+        let handle = open_device_authenticated(device, username, password, self)?;
+        self.remember_device(device);
+        Ok(handle)
+    }
+
+    /// Remember `device`'s name as the one currently open on this
+    /// connection, so a resync after a dropped link can transparently
+    /// reopen it.
+    ///
+    /// `SaneConnection::open_device`/`open_device_authenticated` already
+    /// call this for you; only call it yourself if you opened the
+    /// device through the free `open_device`/`open_device_authenticated`
+    /// functions instead.
+    pub fn remember_device(&mut self, device: &Device) {
+        self.device_name = Some(device.name.clone());
+    }
+
+    fn is_disconnect(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+
+    /// Drop the socket, reconnect, and re-run `init`/reopen so the
+    /// connection (and any previously opened device) is usable again.
+    fn resync(&mut self) -> io::Result<()> {
+        warn!("Connection to {}:{} dropped, resyncing", self.host, self.port);
+
+        self.send_queue.clear();
+        self.recv_buffer.clear();
+
+        self.stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        self.stream.set_nonblocking(true)?;
+
+        init(self).map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+
+        if let Some(name) = self.device_name.clone() {
+            self.reopen(&name)
+                .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-open the device named `name` after a resync.
+    ///
+    /// This can't go through the free `open_device`, which needs a full
+    /// `Device`, not just the name `remember_device` kept; it also can't
+    /// answer an auth challenge, since `resync` has no credentials to
+    /// retry with.
+    fn reopen(&mut self, name: &str) -> Result<()> {
+        write_message(
+            &Message::OpenRequest {
+                name: name.to_owned(),
+            },
+            self,
+        )?;
+
+        match read_message(Some(MessageKind::Open), self)? {
+            Message::OpenReply {
+                status,
+                resource: None,
+                ..
+            } => check_status(status),
+            Message::OpenReply {
+                resource: Some(resource),
+                ..
+            } => Err(Error::BadNetworkDataError(format!(
+                "Device '{}' now requires authentication for resource '{}'; \
+                 a resync has no credentials to retry with",
+                name, resource
+            ))),
+            _ => unreachable!("read_message(Some(MessageKind::Open), ..) always yields OpenReply"),
+        }
+    }
+
+    /// Resync the connection, then report the drop to the caller: the
+    /// request that was in flight when the link went down never reached
+    /// (or never finished being read from) the new socket, so whatever
+    /// called `read`/`write` must treat this as a failed RPC and retry
+    /// the whole thing, not assume a partial success.
+    fn resync_and_reset(&mut self) -> io::Result<()> {
+        self.resync()?;
+        Err(io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+
+    /// Drain as much of the outbound queue as the socket currently
+    /// accepts without blocking; anything left over stays queued.
+    fn flush_queued(&mut self) -> io::Result<()> {
+        while let Some(cursor) = self.send_queue.front_mut() {
+            let position = cursor.position() as usize;
+            let remaining = &cursor.get_ref()[position..];
+
+            if remaining.is_empty() {
+                self.send_queue.pop_front();
+                continue;
+            }
+
+            match self.stream.write(remaining) {
+                Ok(written) => {
+                    let position = cursor.position();
+                    cursor.set_position(position + written as u64);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if Self::is_disconnect(err) => return self.resync_and_reset(),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block until at least one byte is read into the receive buffer,
+    /// tolerating short reads and `WouldBlock` by polling the
+    /// (permanently non-blocking) socket until data actually arrives.
+    /// Each poll first flushes whatever is still queued for sending, so a
+    /// request that an earlier `write` could only partly send still goes out.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            self.flush_queued()?;
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return self.resync_and_reset(),
+                Ok(n) => {
+                    self.recv_buffer.extend_from_slice(&chunk[..n]);
+                    return Ok(());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(ref err) if Self::is_disconnect(err) => return self.resync_and_reset(),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Read for SaneConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // `fill` only guarantees *some* bytes were read; a resync can
+        // leave the buffer empty (the peer hasn't replied to `init` yet),
+        // so keep polling rather than ever reporting `Ok(0)` to a caller
+        // that still wants data - byteorder's readers treat 0 as EOF and
+        // abort. A resync itself is reported as an error (see
+        // `resync_and_reset`), so this loop never spins waiting on a
+        // reply to a request the new connection never received.
+        while self.recv_buffer.is_empty() {
+            self.fill()?;
+        }
+
+        let available = self.recv_buffer.len().min(buf.len());
+        buf[..available].copy_from_slice(&self.recv_buffer[..available]);
+        self.recv_buffer.drain(..available);
+
+        Ok(available)
+    }
+}
+
+impl Write for SaneConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_queue.push_back(Cursor::new(buf.to_vec()));
+        self.flush_queued()?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_queued()?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Build a `SaneConnection` around an already-connected socket,
+    /// bypassing `connect`'s `init` handshake so tests can drive `Read`
+    /// directly. `host`/`port` only matter for tests that trigger a
+    /// resync, which reconnects to them.
+    fn raw_connection(stream: TcpStream, host: &str, port: u16) -> SaneConnection {
+        stream.set_nonblocking(true).unwrap();
+
+        SaneConnection {
+            stream,
+            host: host.to_owned(),
+            port,
+            device_name: None,
+            recv_buffer: Vec::new(),
+            send_queue: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn read_waits_out_a_reply_split_across_two_writes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.write_all(&[1, 2]).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            socket.write_all(&[3, 4]).unwrap();
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+        let mut connection = raw_connection(client, "", 0);
+
+        // Before the fix this could return `Ok(0)` as soon as the socket
+        // had nothing buffered yet, which `read_exact` treats as EOF.
+        let mut received = [0u8; 4];
+        connection.read_exact(&mut received).unwrap();
+
+        assert_eq!(received, [1, 2, 3, 4]);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn read_reports_connection_reset_instead_of_hanging_after_a_dropped_link() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            // First connection: drop it immediately to simulate the link
+            // going down mid-request.
+            let (first, _) = listener.accept().unwrap();
+            drop(first);
+
+            // The resync this triggers reconnects and resends `init`;
+            // answer it with a `SANE_Status::Success`/version-0 reply so
+            // the resync itself succeeds. If the fix didn't surface an
+            // error afterward, the original `read` below would just
+            // keep polling forever for a reply to the request that was
+            // lost with the first connection.
+            let (mut second, _) = listener.accept().unwrap();
+            second.write_all(&[0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+        let mut connection = raw_connection(client, "127.0.0.1", addr.port());
+
+        let mut buf = [0u8; 1];
+        let err = connection.read(&mut buf).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        server.join().unwrap();
+    }
+}
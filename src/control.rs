@@ -0,0 +1,252 @@
+use std::io::prelude::*;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::codec::{read_message, write_message, Message, MessageKind};
+use crate::error::Error;
+use crate::Result;
+
+bitflags! {
+    /// Flags returned alongside a `control_option` reply, indicating what
+    /// the caller must do in response to a successful `SET_VALUE`/`SET_AUTO`.
+    pub struct OptionInfo: i32 {
+        const INEXACT = 0b001;
+        const RELOAD_OPTIONS = 0b010;
+        const RELOAD_PARAMS = 0b100;
+    }
+}
+
+/// Which action a `control_option` call performs, per `SANE_Action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionAction {
+    GetValue,
+    SetValue,
+    SetAuto,
+}
+
+impl OptionAction {
+    pub(crate) fn as_i32(self) -> i32 {
+        match self {
+            OptionAction::GetValue => 0,
+            OptionAction::SetValue => 1,
+            OptionAction::SetAuto => 2,
+        }
+    }
+
+    pub(crate) fn from_i32(value: i32) -> Result<OptionAction> {
+        match value {
+            0 => Ok(OptionAction::GetValue),
+            1 => Ok(OptionAction::SetValue),
+            2 => Ok(OptionAction::SetAuto),
+            _ => Err(Error::BadNetworkDataError(format!(
+                "Unknown option action {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// The value carried by an option, tagged with its `SANE_Value_Type`.
+#[derive(Debug, Clone)]
+pub enum OptionValue {
+    Bool(bool),
+    Int(i32),
+    Fixed(i32),
+    String(String),
+}
+
+impl OptionValue {
+    pub(crate) fn type_tag(&self) -> i32 {
+        match self {
+            OptionValue::Bool(_) => 0,
+            OptionValue::Int(_) => 1,
+            OptionValue::Fixed(_) => 2,
+            OptionValue::String(_) => 3,
+        }
+    }
+
+    pub(crate) fn size(&self) -> i32 {
+        match self {
+            OptionValue::Bool(_) | OptionValue::Int(_) | OptionValue::Fixed(_) => 4,
+            OptionValue::String(value) => value.len() as i32 + 1,
+        }
+    }
+
+    /// Write this value's raw payload: exactly `self.size()` bytes, with
+    /// no additional length prefix of its own (the `size` field sent
+    /// immediately before the payload, per `control_option`, already says
+    /// how many bytes to expect).
+    pub(crate) fn write_payload<S: Write>(&self, stream: &mut S) -> Result<()> {
+        match self {
+            OptionValue::Bool(value) => stream.write_i32::<BigEndian>(*value as i32)?,
+            OptionValue::Int(value) | OptionValue::Fixed(value) => {
+                stream.write_i32::<BigEndian>(*value)?
+            }
+            OptionValue::String(value) => {
+                stream.write_all(value.as_bytes())?;
+                stream.write_all(&[0u8])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a payload as written by `write_payload`: a single 4-byte word
+    /// for bool/int/fixed values, whatever `size` says, and exactly `size`
+    /// bytes for any other type.
+    pub(crate) fn read<S: Read>(type_tag: i32, size: i32, stream: &mut S) -> Result<OptionValue> {
+        match type_tag {
+            0 => Ok(OptionValue::Bool(stream.read_i32::<BigEndian>()? != 0)),
+            1 => Ok(OptionValue::Int(stream.read_i32::<BigEndian>()?)),
+            2 => Ok(OptionValue::Fixed(stream.read_i32::<BigEndian>()?)),
+            3 => {
+                let mut buffer = vec![0u8; size.max(0) as usize];
+                stream.read_exact(&mut buffer)?;
+
+                // Drop the NUL terminator `write_payload` appended.
+                if buffer.last() == Some(&0) {
+                    buffer.pop();
+                }
+
+                Ok(OptionValue::String(String::from_utf8(buffer)?))
+            }
+            _ => {
+                // Button/group options carry no value we can decode; skip it.
+                let mut discard = vec![0u8; size.max(0) as usize];
+                stream.read_exact(&mut discard)?;
+                Ok(OptionValue::Int(0))
+            }
+        }
+    }
+}
+
+/// Result of a successful `control_option` call.
+#[derive(Debug, Clone)]
+pub struct ControlOptionResult {
+    pub info: OptionInfo,
+    pub value: OptionValue,
+    pub resource: Option<String>,
+}
+
+/// Get or set the value of option `index` on `handle`, per
+/// `SANE_NET_CONTROL_OPTION`.
+///
+/// The returned `info` flags tell the caller whether a successful
+/// `SetValue`/`SetAuto` invalidated previously fetched option descriptors
+/// or scan parameters.
+pub fn control_option<S: Read + Write>(
+    handle: i32,
+    index: i32,
+    action: OptionAction,
+    value: OptionValue,
+    stream: &mut S,
+) -> Result<ControlOptionResult> {
+    info!("Controlling option {} on handle: {}", index, handle);
+
+    write_message(
+        &Message::ControlOptionRequest {
+            handle,
+            index,
+            action,
+            value,
+        },
+        stream,
+    )?;
+
+    match read_message(Some(MessageKind::ControlOption), stream)? {
+        Message::ControlOptionReply {
+            status,
+            info,
+            value,
+            resource,
+        } => {
+            crate::check_status(status)?;
+
+            Ok(ControlOptionResult {
+                info,
+                value,
+                resource,
+            })
+        }
+        _ => unreachable!(
+            "read_message(Some(MessageKind::ControlOption), ..) always yields ControlOptionReply"
+        ),
+    }
+}
+
+/// Control option `index` on `handle`, transparently authorizing with
+/// `username`/`password` and retrying once if the server comes back with
+/// an auth `resource` instead of a value, the same way
+/// `open_device_authenticated` handles `OpenResult::AuthRequired`.
+pub fn control_option_authenticated<S: Read + Write>(
+    handle: i32,
+    index: i32,
+    action: OptionAction,
+    value: OptionValue,
+    username: &str,
+    password: &str,
+    stream: &mut S,
+) -> Result<ControlOptionResult> {
+    let result = control_option(handle, index, action, value.clone(), stream)?;
+
+    match result.resource {
+        None => Ok(result),
+        Some(resource) => {
+            crate::authorize(&resource, username, password, stream)?;
+
+            let result = control_option(handle, index, action, value, stream)?;
+
+            match result.resource {
+                None => Ok(result),
+                Some(resource) => Err(Error::BadNetworkDataError(format!(
+                    "Option {} on handle {} still requires authentication for resource '{}' \
+                     after authorizing",
+                    index, handle, resource
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn string_value_size_matches_encoded_payload_length() {
+        let value = OptionValue::String("flatbed".to_owned());
+
+        let mut buf = Vec::new();
+        value.write_payload(&mut buf).unwrap();
+
+        assert_eq!(buf.len(), value.size() as usize);
+    }
+
+    #[test]
+    fn string_value_round_trips_through_write_payload_and_read() {
+        let value = OptionValue::String("flatbed".to_owned());
+
+        let mut buf = Vec::new();
+        value.write_payload(&mut buf).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        let decoded = OptionValue::read(3, value.size(), &mut cursor).unwrap();
+
+        match decoded {
+            OptionValue::String(s) => assert_eq!(s, "flatbed"),
+            other => panic!("unexpected value: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn option_action_round_trips_through_i32() {
+        for action in vec![
+            OptionAction::GetValue,
+            OptionAction::SetValue,
+            OptionAction::SetAuto,
+        ] {
+            assert_eq!(OptionAction::from_i32(action.as_i32()).unwrap(), action);
+        }
+    }
+}
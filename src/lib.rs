@@ -5,17 +5,30 @@ extern crate bitflags;
 extern crate byteorder;
 #[macro_use]
 extern crate log;
+extern crate md5;
 
 pub mod error;
 pub mod status;
 pub mod types;
+mod codec;
+mod connection;
+mod control;
 mod device;
+mod scan;
 
 use std::io::prelude::*;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
 
+use codec::{read_message, write_message};
+pub use codec::{decode, encode, Message, MessageKind};
+pub use connection::SaneConnection;
+pub use control::{
+    control_option, control_option_authenticated, ControlOptionResult, OptionAction, OptionInfo,
+    OptionValue,
+};
 pub use device::Device;
+pub use scan::{Frame, FrameFormat, FrameIter, SaneParameters, ScanSession, StartScanResult};
 use error::Error;
 use status::Status;
 use types::*;
@@ -43,30 +56,31 @@ pub enum OpenResult {
     AuthRequired(String),
 }
 
-pub fn init<S: Read + Write>(stream: &mut S) {
+pub fn init<S: Read + Write>(stream: &mut S) -> Result<u32> {
     info!("Initializing connection");
 
-    let _ = stream.write_u32::<BigEndian>(0);
-    let _ = stream.write_u32::<BigEndian>(SANE_VERSION);
-
-    // zero-length array: username
-    //let _ = stream.write_u32::<BigEndian>(0);
-
-    write_string("Foobar", stream).ok();
-
-    // Make sure we received Success status
-    check_success_status(stream).ok();
-
-    let version = stream.read_u32::<BigEndian>().unwrap();
-
-    println!("Connection initiated, version {:x}", version);
+    write_message(
+        &Message::InitRequest {
+            version: SANE_VERSION,
+            username: "Foobar".to_owned(),
+        },
+        stream,
+    )?;
+
+    match read_message(Some(MessageKind::Init), stream)? {
+        Message::InitReply { status, version } => {
+            check_status(status)?;
+            info!("Connection initiated, version {:x}", version);
+            Ok(version)
+        }
+        _ => unreachable!("read_message(Some(MessageKind::Init), ..) always yields InitReply"),
+    }
 }
 
 pub fn request_device_list<S: Read + Write>(stream: &mut S) -> Result<Vec<Device>> {
     info!("Requesting device list");
 
-    // Send Command
-    stream.write_i32::<BigEndian>(1).ok();
+    write_message(&Message::GetDeviceListRequest, stream)?;
 
     // Make sure we received Success status
     check_success_status(stream)?;
@@ -84,38 +98,109 @@ pub fn request_device_list<S: Read + Write>(stream: &mut S) -> Result<Vec<Device
 pub fn open_device<S: Read + Write>(device: &Device, stream: &mut S) -> Result<OpenResult> {
     info!("Opening device '{}'", device.name);
 
-    // Send Command
-    stream.write_i32::<BigEndian>(2).ok();
-
-    // Send name of device to open
-    write_string(&device.name, stream)?;
-
-    // Make sure we received Success status
-    check_success_status(stream)?;
+    write_message(
+        &Message::OpenRequest {
+            name: device.name.clone(),
+        },
+        stream,
+    )?;
+
+    match read_message(Some(MessageKind::Open), stream)? {
+        Message::OpenReply {
+            status,
+            handle,
+            resource,
+        } => {
+            check_status(status)?;
+
+            match resource {
+                // If no resource is returned, the device was successfully opened
+                None => Ok(OpenResult::Handle(handle)),
+                // Otherwise, authentication is required
+                Some(resource) => Ok(OpenResult::AuthRequired(resource)),
+            }
+        }
+        _ => unreachable!("read_message(Some(MessageKind::Open), ..) always yields OpenReply"),
+    }
+}
 
-    let handle = stream.read_i32::<BigEndian>().unwrap();
-    let resource = <Option<String>>::try_from_stream(stream)?;
+const MD5_MARKER: &str = "$MD5$";
+
+/// Answer an `OpenResult::AuthRequired`/`ControlOptionResult` auth
+/// challenge for `resource`, per `SANE_NET_AUTHORIZE`.
+///
+/// If `resource` carries an `$MD5$<salt>` marker, the password is sent as
+/// `$MD5$` followed by the hex digest of `salt + password`; otherwise the
+/// password is sent in plaintext, as the saned wire protocol requires.
+pub fn authorize<S: Read + Write>(
+    resource: &str,
+    username: &str,
+    password: &str,
+    stream: &mut S,
+) -> Result<()> {
+    info!("Authorizing resource '{}'", resource);
+
+    let password = match resource.find(MD5_MARKER) {
+        Some(index) => {
+            let salt = &resource[index + MD5_MARKER.len()..];
+            let digest = md5::compute(format!("{}{}", salt, password));
+            format!("{}{:x}", MD5_MARKER, digest)
+        }
+        None => password.to_owned(),
+    };
+
+    write_message(
+        &Message::AuthorizeRequest {
+            resource: resource.to_owned(),
+            username: username.to_owned(),
+            password,
+        },
+        stream,
+    )?;
+
+    match read_message(Some(MessageKind::Authorize), stream)? {
+        Message::AuthorizeReply { status } => check_status(status),
+        _ => unreachable!("read_message(Some(MessageKind::Authorize), ..) always yields AuthorizeReply"),
+    }
+}
 
-    match resource {
-        // If no resource is returned, the device was successfully opened
-        None => Ok(OpenResult::Handle(handle)),
-        // Otherwise, authentication is required
-        Some(resource) => Ok(OpenResult::AuthRequired(resource)),
+/// Open `device`, transparently authorizing with `username`/`password` if
+/// the server responds with `OpenResult::AuthRequired`, and retrying the
+/// open once authorization succeeds.
+pub fn open_device_authenticated<S: Read + Write>(
+    device: &Device,
+    username: &str,
+    password: &str,
+    stream: &mut S,
+) -> Result<i32> {
+    match open_device(device, stream)? {
+        OpenResult::Handle(handle) => Ok(handle),
+        OpenResult::AuthRequired(resource) => {
+            authorize(&resource, username, password, stream)?;
+
+            match open_device(device, stream)? {
+                OpenResult::Handle(handle) => Ok(handle),
+                OpenResult::AuthRequired(resource) => Err(Error::BadNetworkDataError(format!(
+                    "Device '{}' still requires authentication for resource '{}' after authorizing",
+                    device.name, resource
+                ))),
+            }
+        }
     }
 }
 
-pub fn close_device<S: Read + Write>(handle: i32, stream: &mut S) {
+pub fn close_device<S: Read + Write>(handle: i32, stream: &mut S) -> Result<()> {
     info!("Closing device using handle: {}", handle);
 
-    // Send Command
-    stream.write_i32::<BigEndian>(3).ok();
-
-    // Send handle
-    stream.write_i32::<BigEndian>(handle).ok();
+    write_message(&Message::CloseRequest { handle }, stream)?;
 
-    // Receive dummy
-    let dummy = stream.read_i32::<BigEndian>().unwrap();
-    debug!("Received dummy value {}", dummy);
+    match read_message(Some(MessageKind::Close), stream)? {
+        Message::CloseReply { dummy } => {
+            debug!("Received dummy value {}", dummy);
+            Ok(())
+        }
+        _ => unreachable!("read_message(Some(MessageKind::Close), ..) always yields CloseReply"),
+    }
 }
 
 pub fn get_option_descriptors<S: Read + Write>(
@@ -124,45 +209,108 @@ pub fn get_option_descriptors<S: Read + Write>(
 ) -> Result<Vec<Option<OptionDescriptor>>> {
     info!("Requesting options for device: {}", handle);
 
-    // Send Command
-    stream.write_i32::<BigEndian>(4).ok();
-
-    // Send handle
-    stream.write_i32::<BigEndian>(handle).ok();
+    write_message(&Message::GetOptionDescriptorsRequest { handle }, stream)?;
 
     <_>::try_from_stream(stream)
 }
 
-fn write_string<S, I: Read + Write>(string: S, stream: &mut I) -> Result<()>
-where
-    S: AsRef<str>,
-{
-    use std::iter::repeat;
-    // Get the &str
-    let string = string.as_ref();
-
-    // Make sure the length of the string fits into 32 bits
-    // Worst case, usize is < 32 bits, in which case, the length definitely fits.
-    if string.len() > i32::max_value() as usize {
-        return Err(Error::BadNetworkDataError(format!(
-            "String length of {} exceeds maximum possible length of {}!",
-            string.len(),
-            i32::max_value()
-        )));
+pub fn get_parameters<S: Read + Write>(handle: i32, stream: &mut S) -> Result<SaneParameters> {
+    info!("Requesting scan parameters for handle: {}", handle);
+
+    write_message(&Message::GetParametersRequest { handle }, stream)?;
+
+    match read_message(Some(MessageKind::GetParameters), stream)? {
+        Message::GetParametersReply { status, parameters } => {
+            check_status(status)?;
+            Ok(parameters)
+        }
+        _ => unreachable!(
+            "read_message(Some(MessageKind::GetParameters), ..) always yields GetParametersReply"
+        ),
     }
+}
 
-    let length = string.len() as i32;
+/// Start a scan on `handle`, opening the secondary data connection to
+/// `host` that the resulting pixel data is read over.
+///
+/// Mirrors `open_device`: a `StartScanResult::AuthRequired` resource
+/// means the server wants authorization before it will hand out a data
+/// connection.
+pub fn start_scan<S: Read + Write>(
+    handle: i32,
+    host: &str,
+    stream: &mut S,
+) -> Result<StartScanResult> {
+    info!("Starting scan on handle: {}", handle);
+
+    write_message(&Message::StartScanRequest { handle }, stream)?;
+
+    match read_message(Some(MessageKind::StartScan), stream)? {
+        Message::StartScanReply {
+            status,
+            port,
+            byte_order,
+            resource,
+        } => {
+            check_status(status)?;
+
+            match resource {
+                None => ScanSession::connect(host, port as u16, byte_order)
+                    .map(StartScanResult::Session),
+                Some(resource) => Ok(StartScanResult::AuthRequired(resource)),
+            }
+        }
+        _ => unreachable!(
+            "read_message(Some(MessageKind::StartScan), ..) always yields StartScanReply"
+        ),
+    }
+}
+
+/// Start a scan on `handle`, transparently authorizing with
+/// `username`/`password` and retrying once if the server responds with
+/// `StartScanResult::AuthRequired`.
+pub fn start_scan_authenticated<S: Read + Write>(
+    handle: i32,
+    host: &str,
+    username: &str,
+    password: &str,
+    stream: &mut S,
+) -> Result<ScanSession> {
+    match start_scan(handle, host, stream)? {
+        StartScanResult::Session(session) => Ok(session),
+        StartScanResult::AuthRequired(resource) => {
+            authorize(&resource, username, password, stream)?;
+
+            match start_scan(handle, host, stream)? {
+                StartScanResult::Session(session) => Ok(session),
+                StartScanResult::AuthRequired(resource) => Err(Error::BadNetworkDataError(format!(
+                    "Handle {} still requires authentication for resource '{}' after authorizing",
+                    handle, resource
+                ))),
+            }
+        }
+    }
+}
 
-    // Double check that we didn't cut the string short
-    assert!(string.len() == length as usize);
+pub fn cancel<S: Read + Write>(handle: i32, stream: &mut S) -> Result<()> {
+    info!("Cancelling scan on handle: {}", handle);
 
-    let length = length + 1;
+    write_message(&Message::CancelRequest { handle }, stream)?;
 
-    stream.write_i32::<BigEndian>(length).ok();
-    stream.write_all(string.as_bytes()).ok();
-    stream.write(&vec![0x00u8]);
+    match read_message(Some(MessageKind::Cancel), stream)? {
+        Message::CancelReply { status } => check_status(status),
+        _ => unreachable!("read_message(Some(MessageKind::Cancel), ..) always yields CancelReply"),
+    }
+}
 
-    Ok(())
+/// Scan `handle` and iterate over the resulting frames, reading each
+/// one's data connection fully before yielding it.
+pub fn scan_frames<'a, S: Read + Write>(
+    handle: i32,
+    host: &str,
+    stream: &'a mut S,
+) -> FrameIter<'a, S> {
+    FrameIter::new(handle, host.to_owned(), stream)
 }
 
 fn read_status<S: Read>(stream: &mut S) -> Result<Status> {
@@ -178,10 +326,56 @@ fn check_success_status<S: Read + Write>(stream: &mut S) -> Result<()> {
     }
 }
 
+/// Return Err unless `status`, as carried raw in a decoded `Message`
+/// reply, is `Status::Success`.
+pub(crate) fn check_status(status: i32) -> Result<()> {
+    match Status::from(status) {
+        Status::Success => Ok(()),
+        err => Err(err.into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn authorize_sends_md5_of_salt_then_password_as_lowercase_hex() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+
+            let mut buf = Vec::new();
+            let message = loop {
+                let mut chunk = [0u8; 256];
+                let n = socket.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+
+                if let Some(message) = decode(None, &mut buf).unwrap() {
+                    break message;
+                }
+            };
+
+            let password = match message {
+                Message::AuthorizeRequest { password, .. } => password,
+                other => panic!("unexpected message: {:?}", other),
+            };
+
+            write_message(&Message::AuthorizeReply { status: 0 }, &mut socket).unwrap();
+
+            password
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let resource = format!("backend:dev0:{}deadbeef", MD5_MARKER);
+        authorize(&resource, "alice", "hunter2", &mut client).unwrap();
+
+        let password = server.join().unwrap();
+        let digest = md5::compute("deadbeefhunter2");
+        assert_eq!(password, format!("{}{:x}", MD5_MARKER, digest));
     }
 }
@@ -0,0 +1,187 @@
+use std::io::prelude::*;
+use std::net::TcpStream;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::error::Error;
+use crate::{Result, TryFromStream};
+
+/// Frame format reported by `get_parameters`, mirroring `SANE_Frame`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Gray = 0,
+    Rgb = 1,
+    Red = 2,
+    Green = 3,
+    Blue = 4,
+}
+
+impl From<i32> for FrameFormat {
+    fn from(value: i32) -> FrameFormat {
+        match value {
+            1 => FrameFormat::Rgb,
+            2 => FrameFormat::Red,
+            3 => FrameFormat::Green,
+            4 => FrameFormat::Blue,
+            _ => FrameFormat::Gray,
+        }
+    }
+}
+
+/// Geometry and depth of a single scanned frame, as returned by
+/// `SANE_NET_GET_PARAMETERS`.
+#[derive(Debug, Clone, Copy)]
+pub struct SaneParameters {
+    pub format: FrameFormat,
+    pub last_frame: bool,
+    pub bytes_per_line: i32,
+    pub pixels_per_line: i32,
+    pub lines: i32,
+    pub depth: i32,
+}
+
+impl TryFromStream for SaneParameters {
+    fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
+        Ok(SaneParameters {
+            format: FrameFormat::from(stream.read_i32::<BigEndian>()?),
+            last_frame: stream.read_i32::<BigEndian>()? != 0,
+            bytes_per_line: stream.read_i32::<BigEndian>()?,
+            pixels_per_line: stream.read_i32::<BigEndian>()?,
+            lines: stream.read_i32::<BigEndian>()?,
+            depth: stream.read_i32::<BigEndian>()?,
+        })
+    }
+}
+
+/// Owns the secondary data connection opened to `saned` after a
+/// successful `start_scan`, and streams the raw pixel data it carries.
+pub struct ScanSession {
+    stream: TcpStream,
+    byte_order: i32,
+}
+
+impl ScanSession {
+    pub(crate) fn connect(host: &str, port: u16, byte_order: i32) -> Result<ScanSession> {
+        info!("Opening data connection to {}:{}", host, port);
+
+        let stream = TcpStream::connect((host, port))?;
+
+        Ok(ScanSession { stream, byte_order })
+    }
+
+    /// The byte order word reported alongside the data connection port.
+    pub fn byte_order(&self) -> i32 {
+        self.byte_order
+    }
+
+    /// Read the next length-prefixed record from the data connection.
+    ///
+    /// Returns `Ok(None)` once the server sends the end-of-frame marker
+    /// (a negative length word), at which point this frame is complete.
+    pub fn read_record(&mut self) -> Result<Option<Vec<u8>>> {
+        let length = self.stream.read_i32::<BigEndian>()?;
+
+        if length < 0 {
+            return Ok(None);
+        }
+
+        let mut buffer = vec![0u8; length as usize];
+        self.stream.read_exact(&mut buffer)?;
+
+        Ok(Some(buffer))
+    }
+
+    /// Drain this session's data connection into a single buffer,
+    /// reading records until the server signals end-of-frame.
+    fn read_to_end(mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        while let Some(mut record) = self.read_record()? {
+            data.append(&mut record);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Result of a `start_scan` call: either the data connection is open and
+/// ready to read, or (mirroring `OpenResult`) the server wants
+/// authorization for `resource` before it will start the scan.
+pub enum StartScanResult {
+    Session(ScanSession),
+    AuthRequired(String),
+}
+
+/// A single decoded frame of scan data: the geometry/depth it was
+/// captured with, and the assembled pixel bytes.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub parameters: SaneParameters,
+    pub data: Vec<u8>,
+}
+
+/// Pull-based iterator over the frames produced by a scan.
+///
+/// Most scans are a single frame, but e.g. a three-pass RGB scan yields
+/// one frame per color; this issues a fresh `start_scan` for each frame
+/// until `SaneParameters::last_frame` comes back `true`.
+pub struct FrameIter<'a, S: Read + Write> {
+    handle: i32,
+    host: String,
+    stream: &'a mut S,
+    done: bool,
+}
+
+impl<'a, S: Read + Write> FrameIter<'a, S> {
+    pub(crate) fn new(handle: i32, host: String, stream: &'a mut S) -> FrameIter<'a, S> {
+        FrameIter {
+            handle,
+            host,
+            stream,
+            done: false,
+        }
+    }
+
+    fn read_next_frame(&mut self) -> Result<Frame> {
+        // START first: parameters (especially `last_frame`) are only
+        // authoritative for the frame that was just started, which matters
+        // for a multi-pass scan where each frame reports a different value.
+        let session = match crate::start_scan(self.handle, &self.host, self.stream)? {
+            StartScanResult::Session(session) => session,
+            StartScanResult::AuthRequired(resource) => {
+                return Err(Error::BadNetworkDataError(format!(
+                    "Device requires authentication for resource '{}' mid-scan; \
+                     authorize the handle before iterating frames",
+                    resource
+                )))
+            }
+        };
+
+        let parameters = crate::get_parameters(self.handle, self.stream)?;
+        let data = session.read_to_end()?;
+
+        if parameters.last_frame {
+            self.done = true;
+        }
+
+        Ok(Frame { parameters, data })
+    }
+}
+
+impl<'a, S: Read + Write> Iterator for FrameIter<'a, S> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_next_frame() {
+            Ok(frame) => Some(Ok(frame)),
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}